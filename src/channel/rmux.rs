@@ -1,6 +1,7 @@
 use super::ChannelStream;
 use crate::config::{ChannelConfig, DEFAULT_RELAY_BUF_SIZE};
 
+use crate::mux::noise::{self, StaticKeyPair, TrustedKeySet};
 use crate::rmux::{
     create_stream, new_auth_event, process_rmux_session, read_rmux_event, write_encrypt_event,
     AuthRequest, AuthResponse, CryptoContext, MuxContext, DEFAULT_RECV_BUF_SIZE,
@@ -12,10 +13,63 @@ use crate::utils::{
 use async_tls::TlsConnector;
 use futures::StreamExt;
 use std::io::ErrorKind;
-use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+use std::sync::Arc;
+use tokio::io::{split, AsyncBufRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio_rustls::{rustls, webpki, TlsConnector as RustlsConnector};
 use url::Url;
 
+/// Verifies the server's leaf certificate against a pinned SHA-256
+/// fingerprint instead of consulting the system trust store, so a
+/// `rmuxs://` peer can be authenticated without a public CA.
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl rustls::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let leaf = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let digest = orion::hazardous::hash::sha256::Sha256::digest(&leaf.0)
+            .map_err(|_| rustls::TLSError::General("fingerprint digest failed".into()))?;
+        // Compare in constant time: this is an authentication check, not a
+        // cache lookup, so a timing-leaky `==` on the digest bytes would let
+        // an attacker recover the pinned fingerprint one byte at a time.
+        match orion::util::secure_cmp(digest.as_ref(), &self.fingerprint[..]) {
+            Ok(()) => Ok(rustls::ServerCertVerified::assertion()),
+            Err(_) => Err(rustls::TLSError::General(
+                "server certificate fingerprint mismatch".into(),
+            )),
+        }
+    }
+}
+
+fn rustls_connector(pinned_fingerprint: Option<&[u8; 32]>) -> RustlsConnector {
+    let mut tls_config = rustls::ClientConfig::new();
+    match pinned_fingerprint {
+        Some(fingerprint) => {
+            tls_config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedCertVerifier {
+                    fingerprint: *fingerprint,
+                }));
+        }
+        None => {
+            tls_config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        }
+    }
+    RustlsConnector::from(Arc::new(tls_config))
+}
+
 async fn init_client<'a, R, W>(
     config: ChannelConfig,
     session_id: u32,
@@ -27,15 +81,27 @@ where
     W: AsyncWrite + Unpin + Sized,
 {
     let sid = 0 as u32;
+    let method = String::from(config.cipher.method.as_str());
+
+    // Authenticated X25519 handshake: the client's long-term static keypair
+    // and the set of server static keys it trusts come from `config.cipher`;
+    // the handshake itself is carried over the plaintext `AuthRequest`/
+    // `AuthResponse` pair below, then both `CryptoContext`s are rebuilt on
+    // the derived transport key. Until the handshake completes, the auth
+    // event itself still goes out under `METHOD_NONE` framing.
+    let local_static = StaticKeyPair::from_bytes(&config.cipher.static_key)
+        .map_err(|e| make_io_error(e))?;
+    let local_ephemeral = StaticKeyPair::generate();
+    let trusted = TrustedKeySet::new(config.cipher.trusted_keys.clone());
+
     let auth = AuthRequest {
-        //key: String::from(key),
         method: String::from(config.cipher.method.as_str()),
+        static_pub: local_static.public,
+        ephemeral_pub: local_ephemeral.public,
     };
     let ev = new_auth_event(sid, &auth);
-    let key = String::from(config.cipher.key.as_str());
-    let method = String::from(config.cipher.method.as_str());
-    let mut rctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
-    let mut wctx = CryptoContext::new(method.as_str(), key.as_str(), 0);
+    let mut rctx = CryptoContext::new(crate::mux::crypto::METHOD_NONE, &[], 0);
+    let mut wctx = CryptoContext::new(crate::mux::crypto::METHOD_NONE, &[], 0);
     write_encrypt_event(&mut wctx, wi, ev).await?;
 
     let recv_ev = match read_rmux_event(&mut rctx, ri).await {
@@ -47,8 +113,14 @@ where
         //let _ = c.shutdown(std::net::Shutdown::Both);
         return Err(std::io::Error::from(ErrorKind::ConnectionRefused));
     }
-    let rctx = CryptoContext::new(method.as_str(), key.as_str(), decoded.rand);
-    let wctx = CryptoContext::new(method.as_str(), key.as_str(), decoded.rand);
+    let peer = noise::HandshakeMessage {
+        ephemeral_pub: decoded.ephemeral_pub,
+        static_pub: decoded.static_pub,
+    };
+    let handshake = noise::complete_client(&local_static, &local_ephemeral, &trusted, &peer)
+        .map_err(|e| make_io_error(e))?;
+    let rctx = CryptoContext::new(method.as_str(), &handshake.key, handshake.nonce);
+    let wctx = CryptoContext::new(method.as_str(), &handshake.key, handshake.nonce);
     let ctx = MuxContext::new(
         config.name.as_str(),
         session_id,
@@ -169,6 +241,20 @@ pub async fn init_rmux_client(
                 return rc;
             }
         }
+        "rmuxs" => {
+            let connector = rustls_connector(config.cert_fingerprint.as_ref());
+            let domain = webpki::DNSNameRef::try_from_ascii_str(domain)
+                .map_err(|_| make_io_error("invalid TLS server name"))?;
+            info!("TLS connect {:?}", domain);
+            let tls_stream = connector.connect(domain, conn).await?;
+            let (read, mut write) = split(tls_stream);
+            let mut buf_reader = tokio::io::BufReader::with_capacity(DEFAULT_RECV_BUF_SIZE, read);
+            let rc = init_client(config, session_id, &mut buf_reader, &mut write).await;
+            write.shutdown().await?;
+            if rc.is_err() {
+                return rc;
+            }
+        }
         "wss" => {
             let connector = TlsConnector::default();
             let conn = AsyncTcpStream::new(conn);
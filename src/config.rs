@@ -0,0 +1,42 @@
+//! Channel configuration: connection target plus the cipher settings used
+//! to authenticate and encrypt a mux session.
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_RELAY_BUF_SIZE: usize = 8192;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CipherConfig {
+    pub method: String,
+    /// Legacy pre-handshake key material, retained only so existing configs
+    /// deserialize; session keys are now derived by the X25519 handshake in
+    /// `crate::mux::noise` instead of being padded directly into a
+    /// `CryptoContext`.
+    pub key: String,
+    /// This endpoint's long-term X25519 static private key.
+    pub static_key: [u8; 32],
+    /// Static public keys of peers this endpoint will complete a handshake
+    /// with; any peer whose static key isn't in this set is rejected.
+    pub trusted_keys: Vec<[u8; 32]>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChannelConfig {
+    pub name: String,
+    pub url: String,
+    pub cipher: CipherConfig,
+    pub max_alive_mins: u32,
+    pub sni: Option<String>,
+    pub sni_proxy: Option<String>,
+    pub proxy: Option<String>,
+    /// Expected SHA-256 fingerprint of the server's leaf certificate for
+    /// `rmuxs://` connections; when set, it's checked instead of the system
+    /// trust store.
+    pub cert_fingerprint: Option<[u8; 32]>,
+}
+
+impl ChannelConfig {
+    pub fn relay_buf_size(&self) -> usize {
+        DEFAULT_RELAY_BUF_SIZE
+    }
+}
@@ -6,16 +6,39 @@ use orion::hazardous::aead::{chacha20poly1305, xchacha20poly1305};
 use std::io::{Error, ErrorKind};
 
 use crate::mux::event::*;
+pub use crate::mux::noise;
 
 pub const METHOD_CHACHA20_POLY1305: &str = "chacha20poly1305";
+pub const METHOD_XCHACHA20_POLY1305: &str = "xchacha20poly1305";
 pub const METHOD_NONE: &str = "none";
 
+/// Length in bytes of the random nonce prefixed to every XChaCha20-Poly1305
+/// event body, ahead of the sealed body itself.
+pub const XCHACHA20POLY1305_NONCE_LEN: usize = 24;
+
+/// Marks an event as a rekey boundary announcement rather than payload data;
+/// the peer uses it to confirm both sides are ratcheting in lockstep.
+pub const FLAG_REKEY: u32 = 0x10;
+
+/// Number of events a single key is allowed to encrypt/decrypt before the
+/// symmetric ratchet rotates it, bounding both key and nonce lifetime.
+pub const REKEY_INTERVAL: u64 = 1_000_000;
+
+const REKEY_INFO: &[u8] = b"rsnova-rekey";
+
 pub struct CryptoContext {
-    pub key: String,
+    pub key: [u8; 32],
     pub encrypt_nonce: u64,
     pub decrypt_nonce: u64,
     pub encrypter: EncryptFunc,
     pub decrypter: DecryptFunc,
+    /// Number of events encrypted/decrypted under `key` since the last
+    /// rekey; compared against `rekey_interval` to trigger the next ratchet.
+    pub events_since_rekey: u64,
+    pub rekey_interval: u64,
+    /// Incremented on every rekey and mixed into the HKDF info string so the
+    /// two ends can never derive the same key for two different generations.
+    pub rekey_generation: u32,
 }
 
 type DecryptError = (u32, &'static str);
@@ -27,12 +50,14 @@ impl CryptoContext {
     pub fn encrypt(&mut self, ev: &Event, out: &mut BytesMut) {
         (self.encrypter)(&self, ev, out);
         self.encrypt_nonce = self.encrypt_nonce + 1;
+        self.events_since_rekey = self.events_since_rekey + 1;
     }
     pub fn decrypt(&mut self, buf: &mut BytesMut) -> Result<Event, DecryptError> {
         let r = (self.decrypter)(&self, buf);
         match r {
             Ok(_) => {
                 self.decrypt_nonce = self.decrypt_nonce + 1;
+                self.events_since_rekey = self.events_since_rekey + 1;
             }
             _ => {}
         }
@@ -43,6 +68,29 @@ impl CryptoContext {
         self.decrypt_nonce = nonce;
         self.encrypt_nonce = nonce;
     }
+
+    /// Whether this context has encrypted/decrypted enough events under the
+    /// current key that it should ratchet to the next generation.
+    pub fn rekey_due(&self) -> bool {
+        self.events_since_rekey >= self.rekey_interval
+    }
+
+    /// Advance the symmetric ratchet: derive the next key from the current
+    /// one via HKDF-SHA256 (with the generation counter in the info string so
+    /// replaying an old key can never reproduce a later one), and reset the nonces back
+    /// to zero under that fresh key.
+    pub fn rekey(&mut self) {
+        self.rekey_generation = self.rekey_generation.wrapping_add(1);
+        let mut info = Vec::with_capacity(REKEY_INFO.len() + 4);
+        info.extend_from_slice(REKEY_INFO);
+        info.extend_from_slice(&self.rekey_generation.to_le_bytes());
+        let mut next_key = [0u8; 32];
+        orion::hazardous::kdf::hkdf::sha256::derive_key(&[], &self.key, &info, &mut next_key)
+            .expect("hkdf-sha256 derive_key");
+        self.key = next_key;
+        self.events_since_rekey = 0;
+        self.reset(0);
+    }
 }
 
 pub fn read_encrypt_event<T: AsyncRead>(
@@ -131,33 +179,39 @@ pub fn none_decrypt_event(ctx: &CryptoContext, buf: &mut BytesMut) -> Result<Eve
 
 pub fn chacha20poly1305_encrypt_event(ctx: &CryptoContext, ev: &Event, out: &mut BytesMut) {
     let mut sk: [u8; 10] = Default::default();
-    sk[0..2].copy_from_slice(&ctx.key.as_bytes()[0..2]);
+    sk[0..2].copy_from_slice(&ctx.key[0..2]);
     sk[2..].copy_from_slice(&ctx.encrypt_nonce.to_le_bytes());
     let e1 = skip32::encode(&sk, ev.header.flag_len);
     let e2 = skip32::encode(&sk, ev.header.stream_id);
-    out.put_u32_le(e1);
-    out.put_u32_le(e2);
+    // Bind the ciphertext to the obfuscated header bytes as AEAD associated
+    // data, so flipping a skip32-scrambled header bit fails authentication
+    // instead of silently being accepted.
+    let mut aad = [0u8; EVENT_HEADER_LEN];
+    aad[0..4].copy_from_slice(&e1.to_le_bytes());
+    aad[4..8].copy_from_slice(&e2.to_le_bytes());
+    out.put_slice(&aad);
 
-    if ev.body.len() > 0 {
-        let key = chacha20poly1305::SecretKey::from_slice(&ctx.key.as_bytes()[0..32]).unwrap();
-        let xnonce: u128 = ctx.encrypt_nonce as u128;
-        let dlen = EVENT_HEADER_LEN + 16 + ev.body.len() as usize;
-        out.reserve(dlen);
-        unsafe {
-            out.set_len(dlen);
-        }
-        let nonce = chacha20poly1305::Nonce::from_slice(&xnonce.to_le_bytes()[0..12]).unwrap();
-        match chacha20poly1305::seal(
-            &key,
-            &nonce,
-            &ev.body[..],
-            None,
-            &mut out[EVENT_HEADER_LEN..],
-        ) {
-            Ok(()) => {}
-            Err(e) => {
-                error!("encrypt error:{} {}", e, out.len());
-            }
+    // Seal even a zero-length body: control events like FLAG_FIN and
+    // FLAG_REKEY carry no payload, but their header still needs a tag, or
+    // they'd ride on skip32 obfuscation alone, which is not a MAC.
+    let key = chacha20poly1305::SecretKey::from_slice(&ctx.key[0..32]).unwrap();
+    let xnonce: u128 = ctx.encrypt_nonce as u128;
+    let nonce = chacha20poly1305::Nonce::from_slice(&xnonce.to_le_bytes()[0..12]).unwrap();
+    let dlen = EVENT_HEADER_LEN + 16 + ev.body.len() as usize;
+    out.reserve(dlen);
+    unsafe {
+        out.set_len(dlen);
+    }
+    match chacha20poly1305::seal(
+        &key,
+        &nonce,
+        &ev.body[..],
+        Some(&aad),
+        &mut out[EVENT_HEADER_LEN..],
+    ) {
+        Ok(()) => {}
+        Err(e) => {
+            error!("encrypt error:{} {}", e, out.len());
         }
     }
 }
@@ -170,8 +224,10 @@ pub fn chacha20poly1305_decrypt_event(
         return Err((EVENT_HEADER_LEN as u32 - buf.len() as u32, ""));
     }
     let mut sk: [u8; 10] = Default::default();
-    sk[0..2].copy_from_slice(&ctx.key.as_bytes()[0..2]);
+    sk[0..2].copy_from_slice(&ctx.key[0..2]);
     sk[2..].copy_from_slice(&ctx.decrypt_nonce.to_le_bytes());
+    let mut aad = [0u8; EVENT_HEADER_LEN];
+    aad.copy_from_slice(&buf[0..EVENT_HEADER_LEN]);
     let mut xbuf: [u8; 4] = Default::default();
     xbuf.copy_from_slice(&buf[0..4]);
     let e1 = skip32::decode(&sk, u32::from_le_bytes(xbuf));
@@ -182,31 +238,123 @@ pub fn chacha20poly1305_decrypt_event(
         flag_len: e1,
         stream_id: e2,
     };
-    let flags = header.flags();
-    if (FLAG_DATA != flags && FLAG_AUTH != flags) || 0 == header.len() {
-        buf.advance(EVENT_HEADER_LEN);
-        return Ok(Event {
-            header: header,
-            body: vec![],
-            local: false,
-        });
-    }
-    if buf.len() - EVENT_HEADER_LEN < (header.len() as usize + 16) {
+    // Every event, including zero-length control events like FLAG_FIN and
+    // FLAG_REKEY, was sealed above, so every event must be opened here too —
+    // skip32 obfuscation alone isn't a MAC.
+    let dlen = header.len() as usize;
+    if buf.len() - EVENT_HEADER_LEN < dlen + 16 {
         return Err((
-            header.len() + EVENT_HEADER_LEN as u32 + 16 - buf.len() as u32,
+            (dlen + 16 + EVENT_HEADER_LEN) as u32 - buf.len() as u32,
             "",
         ));
     }
     buf.advance(EVENT_HEADER_LEN);
-    let dlen = header.len() as usize;
     let mut out = Vec::with_capacity(dlen);
     unsafe {
         out.set_len(dlen);
     }
-    let key = chacha20poly1305::SecretKey::from_slice(&ctx.key.as_bytes()[0..32]).unwrap();
+    let key = chacha20poly1305::SecretKey::from_slice(&ctx.key[0..32]).unwrap();
     let xnonce: u128 = ctx.decrypt_nonce as u128;
     let nonce = chacha20poly1305::Nonce::from_slice(&xnonce.to_le_bytes()[0..12]).unwrap();
-    match chacha20poly1305::open(&key, &nonce, &buf[0..dlen + 16], None, &mut out) {
+    match chacha20poly1305::open(&key, &nonce, &buf[0..dlen + 16], Some(&aad), &mut out) {
+        Ok(()) => {}
+        Err(e) => {
+            error!("decrypt error:{} {}", e, out.len());
+            return Err((0, "Decrypt error"));
+        }
+    }
+    buf.advance(dlen + 16);
+    Ok(Event {
+        header: header,
+        body: out,
+        local: false,
+    })
+}
+
+/// Like `chacha20poly1305_encrypt_event`, but seals the body under a fresh
+/// random 24-byte XChaCha20 nonce instead of one derived from `encrypt_nonce`.
+/// The nonce is written as a prefix ahead of the ciphertext, so opening the
+/// body does not depend on the sender's counter. The skip32 header
+/// obfuscation is still keyed by `encrypt_nonce`/`decrypt_nonce`, so the
+/// receiver's counter must still match the sender's to decode the header.
+pub fn xchacha20poly1305_encrypt_event(ctx: &CryptoContext, ev: &Event, out: &mut BytesMut) {
+    let mut sk: [u8; 10] = Default::default();
+    sk[0..2].copy_from_slice(&ctx.key[0..2]);
+    sk[2..].copy_from_slice(&ctx.encrypt_nonce.to_le_bytes());
+    let e1 = skip32::encode(&sk, ev.header.flag_len);
+    let e2 = skip32::encode(&sk, ev.header.stream_id);
+    let mut aad = [0u8; EVENT_HEADER_LEN];
+    aad[0..4].copy_from_slice(&e1.to_le_bytes());
+    aad[4..8].copy_from_slice(&e2.to_le_bytes());
+    out.put_slice(&aad);
+
+    // Seal even a zero-length body: control events like FLAG_FIN and
+    // FLAG_REKEY carry no payload, but their header still needs a tag, or
+    // they'd ride on skip32 obfuscation alone, which is not a MAC.
+    let key = xchacha20poly1305::SecretKey::from_slice(&ctx.key[0..32]).unwrap();
+    let nonce = xchacha20poly1305::Nonce::generate().unwrap();
+    let dlen = EVENT_HEADER_LEN + XCHACHA20POLY1305_NONCE_LEN + 16 + ev.body.len() as usize;
+    out.reserve(dlen);
+    out.put_slice(nonce.as_ref());
+    unsafe {
+        out.set_len(dlen);
+    }
+    match xchacha20poly1305::seal(
+        &key,
+        &nonce,
+        &ev.body[..],
+        Some(&aad),
+        &mut out[EVENT_HEADER_LEN + XCHACHA20POLY1305_NONCE_LEN..],
+    ) {
+        Ok(()) => {}
+        Err(e) => {
+            error!("encrypt error:{} {}", e, out.len());
+        }
+    }
+}
+
+pub fn xchacha20poly1305_decrypt_event(
+    ctx: &CryptoContext,
+    buf: &mut BytesMut,
+) -> Result<Event, DecryptError> {
+    if buf.len() < EVENT_HEADER_LEN {
+        return Err((EVENT_HEADER_LEN as u32 - buf.len() as u32, ""));
+    }
+    let mut sk: [u8; 10] = Default::default();
+    sk[0..2].copy_from_slice(&ctx.key[0..2]);
+    sk[2..].copy_from_slice(&ctx.decrypt_nonce.to_le_bytes());
+    let mut aad = [0u8; EVENT_HEADER_LEN];
+    aad.copy_from_slice(&buf[0..EVENT_HEADER_LEN]);
+    let mut xbuf: [u8; 4] = Default::default();
+    xbuf.copy_from_slice(&buf[0..4]);
+    let e1 = skip32::decode(&sk, u32::from_le_bytes(xbuf));
+    xbuf.copy_from_slice(&buf[4..8]);
+    let e2 = skip32::decode(&sk, u32::from_le_bytes(xbuf));
+
+    let header = Header {
+        flag_len: e1,
+        stream_id: e2,
+    };
+    // Every event, including zero-length control events like FLAG_FIN and
+    // FLAG_REKEY, was sealed above, so every event must be opened here too —
+    // skip32 obfuscation alone isn't a MAC.
+    let required = header.len() as usize + XCHACHA20POLY1305_NONCE_LEN + 16;
+    if buf.len() - EVENT_HEADER_LEN < required {
+        return Err((
+            (required + EVENT_HEADER_LEN) as u32 - buf.len() as u32,
+            "",
+        ));
+    }
+    buf.advance(EVENT_HEADER_LEN);
+    let nonce = xchacha20poly1305::Nonce::from_slice(&buf[0..XCHACHA20POLY1305_NONCE_LEN]).unwrap();
+    buf.advance(XCHACHA20POLY1305_NONCE_LEN);
+    let dlen = header.len() as usize;
+    let mut out = Vec::with_capacity(dlen);
+    unsafe {
+        out.set_len(dlen);
+    }
+    let key = xchacha20poly1305::SecretKey::from_slice(&ctx.key[0..32]).unwrap();
+    match xchacha20poly1305::open(&key, &nonce, &buf[0..dlen + 16], Some(&aad), &mut out) {
         Ok(()) => {}
         Err(e) => {
             error!("decrypt error:{} {}", e, out.len());
@@ -222,10 +370,19 @@ pub fn chacha20poly1305_decrypt_event(
 }
 
 impl CryptoContext {
-    pub fn new(method: &str, k: &str, nonce: u64) -> Self {
-        let mut key = String::from(k);
-        while key.len() < 32 {
-            key.push('F');
+    /// Build a `CryptoContext` from a raw 32-byte session key, typically the
+    /// output of [`noise::complete_client`]/[`noise::complete_server`], which
+    /// has already replaced the old padded-string derivation with an
+    /// authenticated X25519 handshake.
+    pub fn new(method: &str, k: &[u8], nonce: u64) -> Self {
+        let mut key = [0u8; 32];
+        let n = std::cmp::min(k.len(), key.len());
+        key[..n].copy_from_slice(&k[..n]);
+        // Legacy callers (tests, `METHOD_NONE`) may still hand in a key
+        // shorter than 32 bytes; pad it out deterministically rather than
+        // panicking. Handshake-derived keys are always exactly 32 bytes.
+        for b in key[n..].iter_mut() {
+            *b = b'F';
         }
         match method {
             METHOD_CHACHA20_POLY1305 => CryptoContext {
@@ -234,6 +391,19 @@ impl CryptoContext {
                 decrypt_nonce: nonce,
                 encrypter: chacha20poly1305_encrypt_event,
                 decrypter: chacha20poly1305_decrypt_event,
+                events_since_rekey: 0,
+                rekey_interval: REKEY_INTERVAL,
+                rekey_generation: 0,
+            },
+            METHOD_XCHACHA20_POLY1305 => CryptoContext {
+                key: key,
+                encrypt_nonce: nonce,
+                decrypt_nonce: nonce,
+                encrypter: xchacha20poly1305_encrypt_event,
+                decrypter: xchacha20poly1305_decrypt_event,
+                events_since_rekey: 0,
+                rekey_interval: REKEY_INTERVAL,
+                rekey_generation: 0,
             },
             METHOD_NONE => CryptoContext {
                 key: key,
@@ -241,6 +411,9 @@ impl CryptoContext {
                 decrypt_nonce: nonce,
                 encrypter: none_encrypt_event,
                 decrypter: none_decrypt_event,
+                events_since_rekey: 0,
+                rekey_interval: REKEY_INTERVAL,
+                rekey_generation: 0,
             },
             _ => panic!("not supported crypto method."),
         }
@@ -257,7 +430,7 @@ mod tests {
         let ev = new_fin_event(100, false);
         let mut ctx = CryptoContext::new(
             METHOD_CHACHA20_POLY1305,
-            "21321321321321312321321321212asdfasdasdas1",
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
             21321312,
         );
         let mut buf = BytesMut::new();
@@ -276,7 +449,7 @@ mod tests {
         let ev = new_data_event(100, s.as_bytes(), false);
         let mut ctx = CryptoContext::new(
             METHOD_CHACHA20_POLY1305,
-            "21321321321321312321321321212asdfasdasdas1",
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
             21321312,
         );
         let mut buf = BytesMut::new();
@@ -305,7 +478,7 @@ mod tests {
         let ev = new_fin_event(100, false);
         let mut ctx = CryptoContext::new(
             "none",
-            "21321321321321312321321321212asdfasdasdas1",
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
             21321312,
         );
         let mut buf = BytesMut::new();
@@ -324,7 +497,7 @@ mod tests {
         let ev = new_data_event(100, s.as_bytes(), false);
         let mut ctx = CryptoContext::new(
             "none",
-            "21321321321321312321321321212asdfasdasdas1",
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
             21321312,
         );
         let mut buf = BytesMut::new();
@@ -348,4 +521,80 @@ mod tests {
         assert_eq!(str::from_utf8(&r.body[..]).unwrap(), s);
     }
 
+    #[test]
+    fn test_crypto_rekey() {
+        let mut sender = CryptoContext::new(
+            METHOD_CHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
+            0,
+        );
+        let mut receiver = CryptoContext::new(
+            METHOD_CHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
+            0,
+        );
+        assert!(!sender.rekey_due());
+        sender.events_since_rekey = sender.rekey_interval;
+        assert!(sender.rekey_due());
+
+        sender.rekey();
+        receiver.rekey();
+        assert_eq!(sender.key, receiver.key);
+        assert_eq!(sender.rekey_generation, 1);
+        assert_eq!(sender.encrypt_nonce, 0);
+
+        let s = "hello,world";
+        let ev = new_data_event(100, s.as_bytes(), false);
+        let mut buf = BytesMut::new();
+        sender.encrypt(&ev, &mut buf);
+        let r = receiver.decrypt(&mut buf).unwrap();
+        assert_eq!(str::from_utf8(&r.body[..]).unwrap(), s);
+    }
+
+    #[test]
+    fn test_crypto_xchacha20poly1305() {
+        let s = "hello,world";
+        let ev = new_data_event(100, s.as_bytes(), false);
+        let mut sender = CryptoContext::new(
+            METHOD_XCHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
+            0,
+        );
+        let mut receiver = CryptoContext::new(
+            METHOD_XCHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
+            0,
+        );
+        let mut buf = BytesMut::new();
+        sender.encrypt(&ev, &mut buf);
+        let r = receiver.decrypt(&mut buf).unwrap();
+        assert_eq!(r.header.stream_id, 100);
+        assert_eq!(r.header.flags(), FLAG_DATA);
+        assert_eq!(buf.len(), 0);
+        assert_eq!(str::from_utf8(&r.body[..]).unwrap(), s);
+    }
+
+    #[test]
+    fn test_crypto_control_event_is_authenticated() {
+        // FLAG_FIN/FLAG_REKEY events have an empty body, but their header
+        // must still carry an AEAD tag rather than riding on skip32
+        // obfuscation alone: flip a ciphertext byte and decryption must fail.
+        let ev = new_fin_event(100, false);
+        let mut sender = CryptoContext::new(
+            METHOD_CHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
+            0,
+        );
+        let mut receiver = CryptoContext::new(
+            METHOD_CHACHA20_POLY1305,
+            "21321321321321312321321321212asdfasdasdas1".as_bytes(),
+            0,
+        );
+        let mut buf = BytesMut::new();
+        sender.encrypt(&ev, &mut buf);
+        assert_eq!(buf.len(), EVENT_HEADER_LEN + 16);
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        assert!(receiver.decrypt(&mut buf).is_err());
+    }
 }
@@ -0,0 +1,378 @@
+//! Session establishment shared by the `rmux`/`rmuxs`/`ws`/`wss` channel
+//! implementations: the `AuthRequest`/`AuthResponse` exchange that carries
+//! the X25519 handshake (see `crate::mux::noise`), and the `MuxContext`/
+//! `process_rmux_session` plumbing that drives the two `CryptoContext`s
+//! once a session is established, including the automatic rekey ratchet.
+
+use std::io;
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub use crate::mux::crypto::CryptoContext;
+use crate::mux::crypto::FLAG_REKEY;
+use crate::mux::event::*;
+use crate::mux::noise::{self, HandshakeMessage, StaticKeyPair, TrustedKeySet};
+use crate::utils::make_io_error;
+
+pub const DEFAULT_RECV_BUF_SIZE: usize = 8192;
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub method: String,
+    pub static_pub: [u8; 32],
+    pub ephemeral_pub: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthResponse {
+    pub success: bool,
+    pub static_pub: [u8; 32],
+    pub ephemeral_pub: [u8; 32],
+}
+
+pub struct MuxContext {
+    pub name: String,
+    pub session_id: u32,
+    pub rctx: CryptoContext,
+    pub wctx: CryptoContext,
+    pub max_alive_secs: u64,
+}
+
+impl MuxContext {
+    pub fn new(
+        name: &str,
+        session_id: u32,
+        rctx: CryptoContext,
+        wctx: CryptoContext,
+        max_alive_secs: u64,
+    ) -> Self {
+        MuxContext {
+            name: String::from(name),
+            session_id,
+            rctx,
+            wctx,
+            max_alive_secs,
+        }
+    }
+}
+
+/// `flag_len` packs the event's flag bits into the high byte and its body
+/// length into the low 24 bits, matching how `CryptoContext`'s skip32
+/// header obfuscation treats the field as a single `u32`.
+fn encode_flag_len(flags: u32, len: u32) -> u32 {
+    (flags << 24) | (len & 0x00FF_FFFF)
+}
+
+pub fn new_auth_event(stream_id: u32, req: &AuthRequest) -> Event {
+    let body = bincode::serialize(req).expect("serialize AuthRequest");
+    Event {
+        header: Header {
+            flag_len: encode_flag_len(FLAG_AUTH, body.len() as u32),
+            stream_id,
+        },
+        body,
+        local: false,
+    }
+}
+
+fn new_auth_response_event(stream_id: u32, resp: &AuthResponse) -> Event {
+    let body = bincode::serialize(resp).expect("serialize AuthResponse");
+    Event {
+        header: Header {
+            flag_len: encode_flag_len(FLAG_AUTH, body.len() as u32),
+            stream_id,
+        },
+        body,
+        local: false,
+    }
+}
+
+/// A rekey boundary announcement: no body, the chosen generation rides in
+/// `stream_id` so the peer can confirm it landed on the same one.
+fn new_rekey_event(generation: u32) -> Event {
+    Event {
+        header: Header {
+            flag_len: encode_flag_len(FLAG_REKEY, 0),
+            stream_id: generation,
+        },
+        body: vec![],
+        local: false,
+    }
+}
+
+pub async fn write_encrypt_event<W: AsyncWrite + Unpin>(
+    ctx: &mut CryptoContext,
+    w: &mut W,
+    ev: Event,
+) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    ctx.encrypt(&ev, &mut buf);
+    w.write_all(&buf[..]).await
+}
+
+pub async fn read_rmux_event<R: AsyncBufRead + Unpin>(
+    ctx: &mut CryptoContext,
+    r: &mut R,
+) -> io::Result<Event> {
+    let mut header_buf = vec![0u8; EVENT_HEADER_LEN];
+    r.read_exact(&mut header_buf).await?;
+    let mut buf = BytesMut::from(&header_buf[..]);
+    match ctx.decrypt(&mut buf) {
+        Ok(ev) => Ok(ev),
+        Err((n, reason)) => {
+            if !reason.is_empty() {
+                return Err(make_io_error(reason));
+            }
+            let mut body_buf = vec![0u8; n as usize];
+            r.read_exact(&mut body_buf).await?;
+            buf.extend_from_slice(&body_buf);
+            ctx.decrypt(&mut buf).map_err(|(_, reason)| {
+                make_io_error(if reason.is_empty() {
+                    "decrypt error"
+                } else {
+                    reason
+                })
+            })
+        }
+    }
+}
+
+/// Server-side mirror of the client's handshake in `channel::rmux::init_client`:
+/// read the client's `AuthRequest`, complete the X25519 handshake, and answer
+/// with an `AuthResponse` carrying our ephemeral/static public keys. Returns
+/// the rebuilt `MuxContext` once both sides agree on the session key and on
+/// the cipher method, or an error after telling the client `success = false`
+/// if its static key isn't in `trusted` or it asked for a different method
+/// than `method` (rather than silently building mismatched encrypt/decrypt
+/// contexts on either end).
+pub async fn accept_client<R, W>(
+    name: &str,
+    session_id: u32,
+    method: &str,
+    local_static: &StaticKeyPair,
+    trusted: &TrustedKeySet,
+    max_alive_secs: u64,
+    ri: &mut R,
+    wi: &mut W,
+) -> io::Result<MuxContext>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut rctx = CryptoContext::new(crate::mux::crypto::METHOD_NONE, &[], 0);
+    let mut wctx = CryptoContext::new(crate::mux::crypto::METHOD_NONE, &[], 0);
+
+    let recv_ev = read_rmux_event(&mut rctx, ri).await?;
+    let req: AuthRequest = bincode::deserialize(&recv_ev.body[..])
+        .map_err(|_| make_io_error("invalid AuthRequest"))?;
+    let method_matches = req.method == method;
+
+    let local_ephemeral = StaticKeyPair::generate();
+    let peer = HandshakeMessage {
+        ephemeral_pub: req.ephemeral_pub,
+        static_pub: req.static_pub,
+    };
+    let handshake = noise::complete_server(local_static, &local_ephemeral, trusted, &peer);
+
+    let resp = AuthResponse {
+        success: method_matches && handshake.is_ok(),
+        static_pub: local_static.public,
+        ephemeral_pub: local_ephemeral.public,
+    };
+    let ev = new_auth_response_event(recv_ev.header.stream_id, &resp);
+    write_encrypt_event(&mut wctx, wi, ev).await?;
+
+    if !method_matches {
+        return Err(make_io_error("client requested an unsupported cipher method"));
+    }
+    let handshake = handshake.map_err(make_io_error)?;
+    let rctx = CryptoContext::new(method, &handshake.key, handshake.nonce);
+    let wctx = CryptoContext::new(method, &handshake.key, handshake.nonce);
+    Ok(MuxContext::new(name, session_id, rctx, wctx, max_alive_secs))
+}
+
+/// Drive the rekey ratchet for an already-authenticated mux session: for
+/// every event read off `ri`, keep `ctx.rctx`'s generation in lockstep with
+/// the peer, and roll `ctx.wctx` to the next generation (announcing it via a
+/// `FLAG_REKEY` event) once its own event count crosses `rekey_interval`. An
+/// incoming `FLAG_REKEY` rekeys the read side and is rejected if the
+/// generation the peer announced doesn't match the one we land on
+/// ourselves, since that means the two ends have desynchronized. The read
+/// side only rotates on such an announcement; a peer that sends a non-rekey
+/// event past `rekey_interval` is rejected the same way.
+///
+/// This loop owns only that rekey handshake — it does not forward `ri`'s
+/// events on to the per-stream relay (`create_stream`/`ChannelStream`);
+/// wiring that up is the caller's job. Until a caller does, nothing but the
+/// `FLAG_REKEY` announcement itself is ever written to `wi`, so
+/// `ctx.wctx.rekey_due()` only fires from the bookkeeping below, not from
+/// live traffic.
+pub async fn process_rmux_session<R, W>(
+    mut ctx: MuxContext,
+    ri: &mut R,
+    wi: &mut W,
+    _relay_buf_size: usize,
+) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        if ctx.wctx.rekey_due() {
+            // Seal the announcement under the *current* key and only rotate
+            // once it's on the wire: the peer can only recognize this event
+            // as `FLAG_REKEY` by decrypting it with its own still-current
+            // context, so sealing it under the already-rotated key would
+            // make it undecryptable and desync the framing permanently.
+            let next_generation = ctx.wctx.rekey_generation.wrapping_add(1);
+            write_encrypt_event(&mut ctx.wctx, wi, new_rekey_event(next_generation)).await?;
+            ctx.wctx.rekey();
+        }
+
+        let ev = read_rmux_event(&mut ctx.rctx, ri).await?;
+        if ev.header.flags() == FLAG_REKEY {
+            ctx.rctx.rekey();
+            if ctx.rctx.rekey_generation != ev.header.stream_id {
+                return Err(make_io_error("rekey generation mismatch"));
+            }
+            continue;
+        }
+        if ctx.rctx.events_since_rekey > ctx.rctx.rekey_interval {
+            // The peer went past the rekey boundary without announcing it.
+            return Err(make_io_error("missing rekey announcement"));
+        }
+        if ev.header.flags() == FLAG_FIN {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::crypto::METHOD_CHACHA20_POLY1305;
+    use crate::mux::event::new_fin_event;
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::BufReader;
+
+    /// Captures whatever gets written to it, so a test can decrypt it back
+    /// afterwards and inspect what actually went out on the wire.
+    struct VecWriter(Vec<u8>);
+
+    impl AsyncWrite for VecWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Exercises `accept_client` and `process_rmux_session` together over the
+    /// same `MuxContext`, the wiring the sender-rotates-before-sending rekey
+    /// bug lived in: a client-shaped `AuthRequest` is handed to `accept_client`
+    /// exactly as `channel::rmux::init_client` would send it, the resulting
+    /// session is driven through a rekey boundary (`rekey_interval` forced to
+    /// 1 with one event already counted against it, so the very first loop
+    /// iteration crosses one), and the announcement
+    /// `process_rmux_session` wrote is decrypted back out to confirm it was
+    /// sealed under the pre-rotation key.
+    #[tokio::test]
+    async fn test_accept_client_then_session_survives_rekey_boundary() {
+        let server_static = StaticKeyPair::generate();
+        let client_static = StaticKeyPair::generate();
+        let client_ephemeral = StaticKeyPair::generate();
+        let server_trusted = TrustedKeySet::new(vec![client_static.public]);
+        let client_trusted = TrustedKeySet::new(vec![server_static.public]);
+
+        let auth = AuthRequest {
+            method: String::from(METHOD_CHACHA20_POLY1305),
+            static_pub: client_static.public,
+            ephemeral_pub: client_ephemeral.public,
+        };
+        let mut none_wctx = CryptoContext::new(crate::mux::crypto::METHOD_NONE, &[], 0);
+        let mut request_buf = BytesMut::new();
+        none_wctx.encrypt(&new_auth_event(0, &auth), &mut request_buf);
+
+        let mut request_reader = BufReader::new(Cursor::new(request_buf[..].to_vec()));
+        let mut response_writer = VecWriter(Vec::new());
+
+        let mut ctx = accept_client(
+            "server",
+            1,
+            METHOD_CHACHA20_POLY1305,
+            &server_static,
+            &server_trusted,
+            60,
+            &mut request_reader,
+            &mut response_writer,
+        )
+        .await
+        .expect("server accepts a trusted, matching-method client");
+
+        // Replay what `init_client` does with the response, to confirm both
+        // ends land on the same transport key/nonce.
+        let mut none_rctx = CryptoContext::new(crate::mux::crypto::METHOD_NONE, &[], 0);
+        let mut response_buf = BytesMut::from(&response_writer.0[..]);
+        let resp_ev = none_rctx
+            .decrypt(&mut response_buf)
+            .expect("decode AuthResponse");
+        let decoded: AuthResponse = bincode::deserialize(&resp_ev.body[..]).unwrap();
+        assert!(decoded.success);
+        let peer = HandshakeMessage {
+            ephemeral_pub: decoded.ephemeral_pub,
+            static_pub: decoded.static_pub,
+        };
+        let handshake =
+            noise::complete_client(&client_static, &client_ephemeral, &client_trusted, &peer)
+                .expect("client completes the handshake");
+        assert_eq!(handshake.key, ctx.rctx.key);
+        assert_eq!(handshake.nonce, ctx.rctx.encrypt_nonce);
+
+        // Force the very first loop iteration of `process_rmux_session` to
+        // cross a rekey boundary on the write side.
+        ctx.wctx.rekey_interval = 1;
+        ctx.wctx.events_since_rekey = 1;
+        let pre_rotation_key = ctx.wctx.key;
+
+        // A FIN event from the peer, sealed under the session's initial
+        // (pre-rekey) state, so the read side of the loop can complete
+        // without needing to rekey itself.
+        let mut peer_wctx =
+            CryptoContext::new(METHOD_CHACHA20_POLY1305, &handshake.key, handshake.nonce);
+        let mut fin_buf = BytesMut::new();
+        peer_wctx.encrypt(&new_fin_event(1, false), &mut fin_buf);
+        let mut session_reader = BufReader::new(Cursor::new(fin_buf[..].to_vec()));
+        let mut session_writer = VecWriter(Vec::new());
+
+        process_rmux_session(ctx, &mut session_reader, &mut session_writer, DEFAULT_RECV_BUF_SIZE)
+            .await
+            .expect("session runs through a rekey boundary and the closing FIN");
+
+        // The announcement must decrypt under the *old* key: a peer still on
+        // `pre_rotation_key` can recover `FLAG_REKEY`/generation 1, which is
+        // exactly what desynced before this fix (it used to be sealed under
+        // the already-rotated key instead).
+        let mut mirror_rctx =
+            CryptoContext::new(METHOD_CHACHA20_POLY1305, &pre_rotation_key, handshake.nonce);
+        let mut announce_buf = BytesMut::from(&session_writer.0[..]);
+        let announce_ev = mirror_rctx
+            .decrypt(&mut announce_buf)
+            .expect("rekey announcement decrypts under the pre-rotation key");
+        assert_eq!(announce_ev.header.flags(), FLAG_REKEY);
+        assert_eq!(announce_ev.header.stream_id, 1);
+    }
+}